@@ -1,7 +1,8 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
 
 use axum::{
     extract::State,
@@ -10,25 +11,162 @@ use axum::{
     routing::get,
     Router,
 };
-use futures::{StreamExt};
+use futures::{SinkExt, StreamExt};
+use futures::stream::{SplitSink, SplitStream};
+use sodiumoxide::crypto::sign::ed25519;
+use prometheus::{Encoder, Histogram, HistogramOpts, IntCounter, IntGauge, Registry, TextEncoder};
 use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
 
 static NEXT_CLIENT_ID: AtomicUsize = AtomicUsize::new(1);
 
+// Rooms are addressed by an opaque string name supplied by the client.
+type RoomId = String;
+
+// Per-client outbound handle: a bounded sender plus the bookkeeping needed to
+// apply backpressure to a slow consumer without stalling the whole broadcast.
+struct Client {
+    tx: mpsc::Sender<Message>,
+    // Total messages dropped because this client's queue was full.
+    dropped: AtomicU64,
+    // Consecutive full-queue events; reset on any successful send.
+    full_strikes: AtomicU64,
+}
+
 #[derive(Clone)]
 struct AppState {
-    // Map client ID to their mpsc sender
-    clients: Arc<Mutex<HashMap<usize, mpsc::UnboundedSender<Message>>>>,
+    // Map client ID to their outbound handle
+    clients: Arc<Mutex<HashMap<usize, Client>>>,
+    // Members of each room, and a reverse index so a disconnecting client
+    // can be pulled out of every room it joined without scanning them all.
+    rooms: Arc<Mutex<HashMap<RoomId, HashSet<usize>>>>,
+    client_rooms: Arc<Mutex<HashMap<usize, HashSet<RoomId>>>>,
+    // Trusted ed25519 public keys. `None` leaves the relay open (no
+    // handshake); `Some` enforces the signed hello before a client is added.
+    allowlist: Option<Arc<HashSet<ed25519::PublicKey>>>,
+    // Verified public key of each connected client, keyed by numeric id so
+    // peers can later be addressed by stable identity.
+    peer_keys: Arc<Mutex<HashMap<usize, ed25519::PublicKey>>>,
+    // Prometheus metrics for connection count and message throughput.
+    metrics: Arc<Metrics>,
+    // How often to ping an idle client, and how long without any inbound
+    // frame before the connection is considered dead and evicted.
+    heartbeat_interval: Duration,
+    idle_timeout: Duration,
+    // Bounded outbound-queue depth per client, and the number of consecutive
+    // full-queue events at which a slow consumer is evicted.
+    channel_capacity: usize,
+    max_full_strikes: u64,
+}
+
+// Operational metrics exposed on `/metrics` in the text exposition format.
+struct Metrics {
+    registry: Registry,
+    connected_clients: IntGauge,
+    messages_received: IntCounter,
+    messages_relayed: IntCounter,
+    clients_dropped: IntCounter,
+    connection_lifetime: Histogram,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new();
+        let connected_clients =
+            IntGauge::new("signalling_connected_clients", "Currently connected clients").unwrap();
+        let messages_received = IntCounter::new(
+            "signalling_messages_received_total",
+            "Messages received from clients",
+        )
+        .unwrap();
+        let messages_relayed = IntCounter::new(
+            "signalling_messages_relayed_total",
+            "Messages relayed to peers",
+        )
+        .unwrap();
+        let clients_dropped = IntCounter::new(
+            "signalling_clients_dropped_total",
+            "Clients removed after a send failure during fan-out",
+        )
+        .unwrap();
+        let connection_lifetime = Histogram::with_opts(HistogramOpts::new(
+            "signalling_connection_lifetime_seconds",
+            "Lifetime of a client connection in seconds",
+        ))
+        .unwrap();
+
+        registry.register(Box::new(connected_clients.clone())).unwrap();
+        registry.register(Box::new(messages_received.clone())).unwrap();
+        registry.register(Box::new(messages_relayed.clone())).unwrap();
+        registry.register(Box::new(clients_dropped.clone())).unwrap();
+        registry.register(Box::new(connection_lifetime.clone())).unwrap();
+
+        Metrics {
+            registry,
+            connected_clients,
+            messages_received,
+            messages_relayed,
+            clients_dropped,
+            connection_lifetime,
+        }
+    }
+}
+
+// Signed hello a client sends in response to the server's nonce challenge.
+// Both fields are hex-encoded.
+#[derive(serde::Deserialize)]
+struct Hello {
+    public_key: String,
+    signature: String,
+}
+
+// Control frames a client can send to manage its room membership.
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Control {
+    Join(RoomId),
+    Leave(RoomId),
+}
+
+// Where a message should go. Decoded from the `to` field of an envelope;
+// a frame that isn't an envelope falls back to `All`, preserving the plain
+// relay-to-my-rooms behaviour.
+#[derive(serde::Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+enum Dest {
+    #[default]
+    All,
+    One(usize),
+    Many(Vec<usize>),
+}
+
+// Routing envelope: addresses a frame to a specific peer or set of peers.
+#[derive(serde::Deserialize)]
+struct Envelope {
+    to: Dest,
 }
 
 #[tokio::main]
 async fn main() {
+    sodiumoxide::init().expect("failed to initialise sodiumoxide");
+
     let state = AppState {
         clients: Arc::new(Mutex::new(HashMap::new())),
+        rooms: Arc::new(Mutex::new(HashMap::new())),
+        client_rooms: Arc::new(Mutex::new(HashMap::new())),
+        // No allowlist configured: the relay stays open by default.
+        allowlist: None,
+        peer_keys: Arc::new(Mutex::new(HashMap::new())),
+        metrics: Arc::new(Metrics::new()),
+        heartbeat_interval: Duration::from_secs(30),
+        idle_timeout: Duration::from_secs(90),
+        channel_capacity: 256,
+        max_full_strikes: 16,
     };
 
     let app = Router::new()
         .route("/ws", get(ws_handler))
+        .route("/metrics", get(metrics_handler))
         .with_state(state);
 
     let addr: SocketAddr = "0.0.0.0:8080".parse().unwrap();
@@ -47,39 +185,182 @@ async fn ws_handler(
     ws.on_upgrade(move |socket| handle_socket(socket, state))
 }
 
+// Render the registered metrics in the Prometheus text exposition format.
+async fn metrics_handler(State(state): State<AppState>) -> impl IntoResponse {
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    encoder
+        .encode(&state.metrics.registry.gather(), &mut buffer)
+        .unwrap();
+    String::from_utf8(buffer).unwrap_or_default()
+}
+
+// Challenge a freshly connected client and verify its signed hello. Returns
+// the verified public key, or `None` if the handshake fails or the key is not
+// on the allowlist — in which case the caller must drop the connection.
+async fn authenticate(
+    ws_sender: &mut SplitSink<WebSocket, Message>,
+    ws_receiver: &mut SplitStream<WebSocket>,
+    allowlist: &HashSet<ed25519::PublicKey>,
+) -> Option<ed25519::PublicKey> {
+    // Challenge the client with a fresh random nonce.
+    let nonce = sodiumoxide::randombytes::randombytes(32);
+    ws_sender
+        .send(Message::Text(hex::encode(&nonce)))
+        .await
+        .ok()?;
+
+    // The first frame back must be the signed hello.
+    let text = match ws_receiver.next().await {
+        Some(Ok(Message::Text(text))) => text,
+        _ => return None,
+    };
+    let hello: Hello = serde_json::from_str(&text).ok()?;
+    let public_key = ed25519::PublicKey::from_slice(&hex::decode(&hello.public_key).ok()?)?;
+    let signature = ed25519::Signature::from_slice(&hex::decode(&hello.signature).ok()?)?;
+
+    // Reject unknown keys and bad signatures over the nonce we just issued.
+    if !allowlist.contains(&public_key) {
+        return None;
+    }
+    if !ed25519::verify_detached(&signature, &nonce, &public_key) {
+        return None;
+    }
+    Some(public_key)
+}
+
 // Per-connection handler
 async fn handle_socket(stream: WebSocket, state: AppState) {
-    use tokio_stream::wrappers::UnboundedReceiverStream;
+    use tokio_stream::wrappers::ReceiverStream;
 
     let client_id = NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed);
     let (mut ws_sender, mut ws_receiver) = stream.split();
 
-    // mpsc channel to send messages *to* this client
-    let (tx, rx) = mpsc::unbounded_channel::<Message>();
-    let rx_stream = UnboundedReceiverStream::new(rx);
+    // When an allowlist is configured, require a signed hello before the
+    // client is inserted anywhere; an unverified socket is simply dropped.
+    let peer_key = if let Some(allowlist) = state.allowlist.clone() {
+        match authenticate(&mut ws_sender, &mut ws_receiver, &allowlist).await {
+            Some(key) => Some(key),
+            None => {
+                println!("Client {client_id} failed authentication, closing");
+                let _ = ws_sender.send(Message::Close(None)).await;
+                return;
+            }
+        }
+    } else {
+        None
+    };
+
+    // Bounded mpsc channel to send messages *to* this client, giving the
+    // relay backpressure instead of unbounded queue growth.
+    let (tx, rx) = mpsc::channel::<Message>(state.channel_capacity);
+    let rx_stream = ReceiverStream::new(rx);
+
+    // Observe this connection's lifetime when the handler returns.
+    let _lifetime = state.metrics.connection_lifetime.start_timer();
 
     // store sender in global client list
+    let heartbeat_tx = tx.clone();
     {
         let mut clients = state.clients.lock().unwrap();
-        clients.insert(client_id, tx);
+        clients.insert(
+            client_id,
+            Client {
+                tx,
+                dropped: AtomicU64::new(0),
+                full_strikes: AtomicU64::new(0),
+            },
+        );
+        state.metrics.connected_clients.inc();
         println!("Client {client_id} connected, total clients: {}", clients.len());
     }
+    if let Some(key) = peer_key {
+        state.peer_keys.lock().unwrap().insert(client_id, key);
+    }
 
-    // Task: forward messages from rx_stream to WebSocket
-    let forward_to_ws = tokio::spawn(async move {
+    // Task: forward messages from rx_stream to WebSocket. Once every sender
+    // is dropped the stream ends; we then flush a clean close frame so the
+    // peer sees an orderly shutdown rather than a severed socket.
+    let mut forward_to_ws = tokio::spawn(async move {
         let mut rx_stream = rx_stream;
         while let Some(msg) = rx_stream.next().await {
             if ws_sender.send(msg).await.is_err() {
-                break;
+                return;
             }
         }
+        let _ = ws_sender.send(Message::Close(None)).await;
     });
 
-    // Read from WebSocket and broadcast to all clients except sender
-    while let Some(Ok(msg)) = ws_receiver.next().await {
+    // Keepalive: track the last time we heard from this client, ping it on an
+    // interval, and trip the shutdown signal once it goes silent past the
+    // idle timeout. `last_seen` holds milliseconds since the connection began.
+    let start = tokio::time::Instant::now();
+    let last_seen = Arc::new(AtomicU64::new(0));
+    let shutdown = Arc::new(tokio::sync::Notify::new());
+    let heartbeat = tokio::spawn({
+        let last_seen = last_seen.clone();
+        let shutdown = shutdown.clone();
+        let interval = state.heartbeat_interval;
+        let idle_timeout = state.idle_timeout;
+        async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                let idle = start
+                    .elapsed()
+                    .saturating_sub(Duration::from_millis(last_seen.load(Ordering::Relaxed)));
+                if idle >= idle_timeout {
+                    shutdown.notify_one();
+                    break;
+                }
+                // A closed channel means the client is gone; a full queue just
+                // means the consumer is busy, so skip this ping.
+                if let Err(TrySendError::Closed(_)) = heartbeat_tx.try_send(Message::Ping(Vec::new())) {
+                    break;
+                }
+            }
+        }
+    });
+
+    // Read from WebSocket and relay to the sender's rooms, bailing out if the
+    // heartbeat task reports the connection as idle.
+    loop {
+        let msg = tokio::select! {
+            maybe = ws_receiver.next() => match maybe {
+                Some(Ok(msg)) => msg,
+                _ => break,
+            },
+            _ = shutdown.notified() => {
+                println!("Client {client_id} idle timeout, closing");
+                break;
+            }
+        };
+
+        // Any inbound frame (including Pong) counts as liveness.
+        last_seen.store(start.elapsed().as_millis() as u64, Ordering::Relaxed);
+
         match msg {
-            Message::Text(_) | Message::Binary(_) => {
-                broadcast(&state, msg, client_id).await;
+            Message::Text(ref text) => {
+                state.metrics.messages_received.inc();
+                // A text frame may be a room control message; handle that
+                // first and don't relay it on to peers.
+                if let Ok(control) = serde_json::from_str::<Control>(text) {
+                    match control {
+                        Control::Join(room) => join_room(&state, client_id, room),
+                        Control::Leave(room) => leave_room(&state, client_id, &room),
+                    }
+                    continue;
+                }
+                // Otherwise treat it as a routing envelope, defaulting to the
+                // room relay when the payload isn't one.
+                let dest = serde_json::from_str::<Envelope>(text)
+                    .map(|e| e.to)
+                    .unwrap_or_default();
+                route(&state, dest, msg, client_id).await;
+            }
+            Message::Binary(_) => {
+                state.metrics.messages_received.inc();
+                relay_to_rooms(&state, msg, client_id).await;
             }
             Message::Close(_) => {
                 break;
@@ -88,37 +369,199 @@ async fn handle_socket(stream: WebSocket, state: AppState) {
         }
     }
 
-    // connection is closing; remove this client
+    // connection is closing; stop pinging and remove this client everywhere
+    heartbeat.abort();
+    remove_from_all_rooms(&state, client_id);
+    state.peer_keys.lock().unwrap().remove(&client_id);
     {
         let mut clients = state.clients.lock().unwrap();
+        // Dropping the stored sender signals end-of-stream to the forwarder.
         clients.remove(&client_id);
+        state.metrics.connected_clients.dec();
         println!("Client {client_id} disconnected, total clients: {}", clients.len());
     }
-    forward_to_ws.abort();
+
+    // Give the forwarder a bounded window to flush anything still queued and
+    // send its close frame; only hard-kill it if the drain stalls.
+    if tokio::time::timeout(FLUSH_TIMEOUT, &mut forward_to_ws).await.is_err() {
+        forward_to_ws.abort();
+    }
 }
 
-// broadcast a message to all connected clients except sender
-async fn broadcast(state: &AppState, msg: Message, sender_id: usize) {
-    let mut to_remove = Vec::new();
+// How long to wait for a disconnecting client's queue to drain before
+// abandoning the flush and tearing the connection down.
+const FLUSH_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+// Add a client to a room, maintaining the reverse index.
+fn join_room(state: &AppState, client_id: usize, room: RoomId) {
+    state
+        .rooms
+        .lock()
+        .unwrap()
+        .entry(room.clone())
+        .or_default()
+        .insert(client_id);
+    state
+        .client_rooms
+        .lock()
+        .unwrap()
+        .entry(client_id)
+        .or_default()
+        .insert(room);
+}
+
+// Remove a client from a single room, dropping the room once it is empty.
+fn leave_room(state: &AppState, client_id: usize, room: &str) {
+    let mut rooms = state.rooms.lock().unwrap();
+    if let Some(members) = rooms.get_mut(room) {
+        members.remove(&client_id);
+        if members.is_empty() {
+            rooms.remove(room);
+        }
+    }
+    drop(rooms);
+    if let Some(joined) = state.client_rooms.lock().unwrap().get_mut(&client_id) {
+        joined.remove(room);
+    }
+}
+
+// Pull a disconnecting client out of every room it had joined.
+fn remove_from_all_rooms(state: &AppState, client_id: usize) {
+    let joined = state.client_rooms.lock().unwrap().remove(&client_id);
+    if let Some(joined) = joined {
+        let mut rooms = state.rooms.lock().unwrap();
+        for room in joined {
+            if let Some(members) = rooms.get_mut(&room) {
+                members.remove(&client_id);
+                if members.is_empty() {
+                    rooms.remove(&room);
+                }
+            }
+        }
+    }
+}
 
+// Dispatch a message according to its envelope destination.
+async fn route(state: &AppState, dest: Dest, msg: Message, sender_id: usize) {
+    match dest {
+        Dest::All => relay_to_rooms(state, msg, sender_id).await,
+        Dest::One(target) => {
+            send_to(state, target, msg);
+        }
+        Dest::Many(targets) => {
+            for target in targets {
+                send_to(state, target, msg.clone());
+            }
+        }
+    }
+}
+
+// Deliver a message to a single client by id, cleaning up if its channel is
+// already closed. Returns whether the target was reachable.
+fn send_to(state: &AppState, target_id: usize, msg: Message) -> bool {
+    let evict = {
+        let clients = state.clients.lock().unwrap();
+        match clients.get(&target_id) {
+            Some(client) => match try_deliver(state, client, msg) {
+                Delivery::Sent | Delivery::Dropped => return true,
+                Delivery::Evict => true,
+            },
+            None => return false,
+        }
+    };
+    if evict {
+        state.clients.lock().unwrap().remove(&target_id);
+        remove_from_all_rooms(state, target_id);
+    }
+    false
+}
+
+// Outcome of attempting a non-blocking delivery to one client.
+enum Delivery {
+    Sent,
+    Dropped,
+    Evict,
+}
+
+// Try to enqueue a message without blocking. A full queue drops the message
+// and counts a strike; too many consecutive strikes, or a closed channel,
+// evicts the slow/dead consumer.
+fn try_deliver(state: &AppState, client: &Client, msg: Message) -> Delivery {
+    match client.tx.try_send(msg) {
+        Ok(()) => {
+            client.full_strikes.store(0, Ordering::Relaxed);
+            state.metrics.messages_relayed.inc();
+            Delivery::Sent
+        }
+        Err(TrySendError::Full(_)) => {
+            client.dropped.fetch_add(1, Ordering::Relaxed);
+            let strikes = client.full_strikes.fetch_add(1, Ordering::Relaxed) + 1;
+            if strikes >= state.max_full_strikes {
+                Delivery::Evict
+            } else {
+                Delivery::Dropped
+            }
+        }
+        Err(TrySendError::Closed(_)) => Delivery::Evict,
+    }
+}
+
+// Relay a message to every room the sender belongs to, de-duplicating
+// recipients that share more than one room with the sender.
+async fn relay_to_rooms(state: &AppState, msg: Message, sender_id: usize) {
+    let joined: Vec<RoomId> = state
+        .client_rooms
+        .lock()
+        .unwrap()
+        .get(&sender_id)
+        .map(|r| r.iter().cloned().collect())
+        .unwrap_or_default();
+
+    let mut seen = HashSet::new();
+    for room in joined {
+        broadcast_to_room(state, &room, msg.clone(), sender_id, &mut seen);
+    }
+}
+
+// Fan a message out to the members of a single room, skipping the sender and
+// any recipient already reached through another shared room.
+fn broadcast_to_room(
+    state: &AppState,
+    room: &str,
+    msg: Message,
+    sender_id: usize,
+    seen: &mut HashSet<usize>,
+) {
+    let members: Vec<usize> = match state.rooms.lock().unwrap().get(room) {
+        Some(members) => members.iter().copied().collect(),
+        None => return,
+    };
+
+    let mut to_remove = Vec::new();
     let clients = state.clients.lock().unwrap();
-    for (&id, client_tx) in clients.iter() {
-        // Don't send back to sender
-        if id == sender_id {
+    for id in members {
+        if id == sender_id || !seen.insert(id) {
             continue;
         }
-        if client_tx.send(msg.clone()).is_err() {
-            // client disconnected, mark for removal
-            to_remove.push(id);
+        if let Some(client) = clients.get(&id) {
+            // A full or closed queue must not stall delivery to everyone else.
+            if let Delivery::Evict = try_deliver(state, client, msg.clone()) {
+                to_remove.push(id);
+            }
         }
     }
     drop(clients);
 
-    // remove disconnected clients
+    // remove slow or disconnected clients
     if !to_remove.is_empty() {
+        state.metrics.clients_dropped.inc_by(to_remove.len() as u64);
         let mut clients = state.clients.lock().unwrap();
+        for id in &to_remove {
+            clients.remove(id);
+        }
+        drop(clients);
         for id in to_remove {
-            clients.remove(&id);
+            remove_from_all_rooms(state, id);
         }
     }
 }